@@ -6,11 +6,21 @@
 #![allow(dead_code)]
 use crate::{page::{zalloc, PAGE_SIZE},
             virtio,
-            virtio::{MmioOffsets, Queue, StatusField, VIRTIO_RING_SIZE}};
-use core::{mem::size_of, ptr::null_mut};
+            virtio::{Descriptor, MmioOffsets, Queue, StatusField, VIRTIO_RING_SIZE,
+                     VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE}};
+use core::{mem::{size_of, MaybeUninit},
+           ptr::{null, null_mut},
+           sync::atomic::{fence, Ordering}};
 
 pub const F_VIRGL: u32 = 0;
 pub const F_EDID: u32 = 1;
+// Feature word 1, bit 0 (i.e. overall feature bit 32).
+pub const VIRTIO_F_VERSION_1: u32 = 1 << 0;
+
+// Until we learn better from CmdGetDisplayInfo/CmdGetEdid, assume a
+// reasonable default mode so we have something to scan out.
+pub const DEFAULT_WIDTH: u32 = 1280;
+pub const DEFAULT_HEIGHT: u32 = 720;
 
 pub const EVENT_DISPLAY: u32 = 1 << 0;
 #[repr(C)]
@@ -24,6 +34,7 @@ pub struct Config {
 	reserved: u32,
 }
 #[repr(u32)]
+#[derive(Clone, Copy)]
 pub enum CtrlType {
 	/* 2d commands */
 	CmdGetDisplayInfo = 0x0100,
@@ -55,8 +66,20 @@ pub enum CtrlType {
 	RespErrInvalidParameter,
 }
 
+// The device can legitimately send back a short response (e.g. RespOkNoData
+// or an error, neither of which fill in a payload). Check the leading
+// ctrl_type against the *specific* discriminant the payload type we're
+// about to assume_init() requires, not just any known Resp* value -- a
+// RespOkNoData answering CmdGetDisplayInfo is a short response exactly like
+// an error is, and must be rejected the same way, or we'd assume_init() a
+// pmodes array the device never wrote.
+fn resp_ctrl_type_is(raw: u32, expected: CtrlType) -> bool {
+	raw == expected as u32
+}
+
 pub const FLAG_FENCE: u32= 1 << 0;
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct CtrlHeader {
 	ctrl_type: CtrlType,
 	flags: u32,
@@ -64,16 +87,32 @@ pub struct CtrlHeader {
 	ctx_id: u32,
 	padding: u32
 }
+impl CtrlHeader {
+	fn new(ctrl_type: CtrlType) -> Self {
+		Self { ctrl_type, flags: 0, fence_id: 0, ctx_id: 0, padding: 0 }
+	}
+
+	fn new_fenced(ctrl_type: CtrlType, fence_id: u64) -> Self {
+		Self { ctrl_type, flags: FLAG_FENCE, fence_id, ctx_id: 0, padding: 0 }
+	}
+}
 
 pub const MAX_SCANOUTS: usize = 16;
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct Rect {
 	x: u32,
 	y: u32,
 	width: u32,
 	height: u32,
 }
+impl Rect {
+	pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+		Self { x, y, width, height }
+	}
+}
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct DisplayOne {
 	r: Rect,
 	enabled: u32,
@@ -132,6 +171,7 @@ pub struct SetScanout {
 	resource_id: u32,
 }
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct ResourceFlush {
 	hdr: CtrlHeader,
 	r: Rect,
@@ -140,6 +180,7 @@ pub struct ResourceFlush {
 }
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct TransferToHost2d {
 	hdr: CtrlHeader,
 	r: Rect,
@@ -174,6 +215,11 @@ pub struct CursorPos {
 	y: u32,
 	padding: u32,
 }
+impl CursorPos {
+	pub fn new(scanout_id: u32, x: u32, y: u32) -> Self {
+		Self { scanout_id, x, y, padding: 0 }
+	}
+}
 
 #[repr(C)]
 pub struct UpdateCursor {
@@ -187,18 +233,559 @@ pub struct UpdateCursor {
 
 
 
+// Pull the preferred detailed timing descriptor (bytes 54..72 of the base
+// EDID block) apart to recover the panel's native resolution.
+fn parse_edid_preferred_mode(edid: &[u8; 1024]) -> Option<(u32, u32)> {
+	let dtd = &edid[54..72];
+	let pixel_clock = u16::from_le_bytes([dtd[0], dtd[1]]);
+	if pixel_clock == 0 {
+		return None;
+	}
+	let width = ((dtd[4] as u32 & 0xf0) << 4) | dtd[2] as u32;
+	let height = ((dtd[7] as u32 & 0xf0) << 4) | dtd[5] as u32;
+	Some((width, height))
+}
+
+// Select queue `queue_sel`, negotiate its size, allocate its ring, and
+// hand the address(es) to the device. `modern` picks between the legacy
+// GuestPageSize/QueuePfn layout and the split QueueDesc/QueueDriver/
+// QueueDevice + QueueReady layout of the VIRTIO_F_VERSION_1 transport;
+// either way the three rings live in the one `Queue`-sized allocation.
+pub(crate) unsafe fn setup_queue(ptr: *mut u32, queue_sel: u32, modern: bool, num_pages: usize) -> Option<*mut Queue> {
+	ptr.add(MmioOffsets::QueueSel.scale32()).write_volatile(queue_sel);
+	let qnmax = ptr.add(MmioOffsets::QueueNumMax.scale32()).read_volatile();
+	ptr.add(MmioOffsets::QueueNum.scale32()).write_volatile(VIRTIO_RING_SIZE as u32);
+	if VIRTIO_RING_SIZE as u32 > qnmax {
+		return None;
+	}
+
+	let queue_ptr = zalloc(num_pages) as *mut Queue;
+
+	if modern {
+		let desc_addr = core::ptr::addr_of!((*queue_ptr).desc) as u64;
+		let avail_addr = core::ptr::addr_of!((*queue_ptr).avail) as u64;
+		let used_addr = core::ptr::addr_of!((*queue_ptr).used) as u64;
+		ptr.add(MmioOffsets::QueueDescLow.scale32()).write_volatile(desc_addr as u32);
+		ptr.add(MmioOffsets::QueueDescHigh.scale32()).write_volatile((desc_addr >> 32) as u32);
+		ptr.add(MmioOffsets::QueueDriverLow.scale32()).write_volatile(avail_addr as u32);
+		ptr.add(MmioOffsets::QueueDriverHigh.scale32()).write_volatile((avail_addr >> 32) as u32);
+		ptr.add(MmioOffsets::QueueDeviceLow.scale32()).write_volatile(used_addr as u32);
+		ptr.add(MmioOffsets::QueueDeviceHigh.scale32()).write_volatile((used_addr >> 32) as u32);
+		ptr.add(MmioOffsets::QueueReady.scale32()).write_volatile(1);
+	} else {
+		// Alignment is very important here. This is the memory address
+		// alignment between the available and used rings. If this is wrong,
+		// then we and the device will refer to different memory addresses
+		// and hence get the wrong data in the used ring.
+		// ptr.add(MmioOffsets::QueueAlign.scale32()).write_volatile(2);
+		let queue_pfn = queue_ptr as u32;
+		ptr.add(MmioOffsets::GuestPageSize.scale32()).write_volatile(PAGE_SIZE as u32);
+		// QueuePFN is a physical page number, however it appears for QEMU
+		// we have to write the entire memory address. This is a physical
+		// memory address where we (the OS) and the device have in common
+		// for making and receiving requests.
+		ptr.add(MmioOffsets::QueuePfn.scale32()).write_volatile(queue_pfn / PAGE_SIZE as u32);
+	}
+
+	Some(queue_ptr)
+}
+
+/// A chain of descriptors submitted to a virtqueue. Builds the chain,
+/// links it into the avail ring, and notifies the device on construction;
+/// `wait` then spins the used ring for completion. Submission and
+/// completion are split like this so a future caller can do other work
+/// between the two instead of blocking the whole kernel on the device.
+pub struct DescriptorChain<'a> {
+	queue:        &'a mut Queue,
+	idx:          &'a mut u16,
+	ack_used_idx: &'a mut u16,
+	head:         u16,
+}
+impl<'a> DescriptorChain<'a> {
+	/// Link `segments` (buffer address, length, device-writable) into
+	/// consecutive descriptors starting at `*idx`, publish the head to
+	/// the avail ring, and notify queue `queue_sel`.
+	pub unsafe fn submit(dev: *mut u32, queue: *mut Queue, idx: &'a mut u16, ack_used_idx: &'a mut u16, queue_sel: u32,
+	                     segments: &[(u64, u32, bool)]) -> Self {
+		let queue_ref = &mut *queue;
+		let head = *idx as usize % VIRTIO_RING_SIZE;
+		let mut pos = head;
+		for (i, &(addr, len, writable)) in segments.iter().enumerate() {
+			let next = (pos + 1) % VIRTIO_RING_SIZE;
+			let mut flags = if writable { VIRTQ_DESC_F_WRITE } else { 0 };
+			if i + 1 < segments.len() {
+				flags |= VIRTQ_DESC_F_NEXT;
+			}
+			queue_ref.desc[pos] = Descriptor { addr, len, flags, next: next as u16 };
+			pos = next;
+		}
+
+		let avail_idx = queue_ref.avail.idx as usize % VIRTIO_RING_SIZE;
+		queue_ref.avail.ring[avail_idx] = head as u16;
+		fence(Ordering::SeqCst);
+		queue_ref.avail.idx = queue_ref.avail.idx.wrapping_add(1);
+
+		dev.add(MmioOffsets::QueueNotify.scale32()).write_volatile(queue_sel);
+		*idx = idx.wrapping_add(segments.len() as u16);
+
+		Self { queue: queue_ref, idx, ack_used_idx, head: head as u16 }
+	}
+
+	/// Spin until this chain lands in the used ring, returning the
+	/// number of bytes the device wrote into the writable segments.
+	/// Used entries of other chains met on the way are stepped over.
+	pub unsafe fn wait(self) -> u32 {
+		loop {
+			while *self.ack_used_idx == self.queue.used.idx {
+				// Spin until the device services the request.
+			}
+			let used = &self.queue.used.ring[*self.ack_used_idx as usize % VIRTIO_RING_SIZE];
+			let (id, written) = (used.id as u16, used.len);
+			*self.ack_used_idx = self.ack_used_idx.wrapping_add(1);
+			if id == self.head {
+				return written;
+			}
+		}
+	}
+}
+
+// How many fenced commands a single GPU device can have in flight at
+// once; each `flush_async` takes two (the transfer and the flush).
+const MAX_PENDING_FENCES: usize = 8;
+
 pub struct Device {
-	queue:        *mut Queue,
-	dev:          *mut u32,
-	idx:          u16,
-	ack_used_idx: u16,
+	queue:               *mut Queue,
+	dev:                 *mut u32,
+	idx:                 u16,
+	ack_used_idx:        u16,
+	cursor_queue:        *mut Queue,
+	cursor_idx:          u16,
+	cursor_ack_used_idx: u16,
+	resource_id:         u32,
+	width:               u32,
+	height:              u32,
+	fb:                  *mut u8,
+	next_fence_id:       u64,
+	// `handle_interrupt` trails the controlq's used ring independently of
+	// `ack_used_idx` (which only `DescriptorChain::wait` touches, for the
+	// fully synchronous calls). Sharing one counter between a polling
+	// consumer and an interrupt consumer of the same queue lets whichever
+	// runs first steal the entry the other is waiting on.
+	fenced_ack_idx:      u16,
+	// (descriptor head, response buffer, whether a caller will poll this
+	// one via `is_fence_complete`). `flush_async`'s transfer command is
+	// fenced so its completion frees its pending slot, but nothing ever
+	// polls its fence id -- tracking that `bool` lets `handle_interrupt`
+	// skip publishing it into `completed_fences`, where it would otherwise
+	// sit forever and eventually fill the pool.
+	pending:             [Option<(u16, *const CtrlHeader, bool)>; MAX_PENDING_FENCES],
+	completed_fences:    [Option<u64>; MAX_PENDING_FENCES],
+	// Per-slot DMA buffers for in-flight fenced commands, indexed in
+	// lockstep with `pending` so a second `flush_async` can't overwrite a
+	// request the device may still be reading.
+	xfer_slots:          [TransferToHost2d; MAX_PENDING_FENCES],
+	flush_slots:         [ResourceFlush; MAX_PENDING_FENCES],
+	fence_resp:          [CtrlHeader; MAX_PENDING_FENCES],
 }
 impl Device {
 	pub const fn new() -> Self {
-		Self { queue:        null_mut(),
-		       dev:          null_mut(),
-		       idx:          0,
-		       ack_used_idx: 0, }
+		const EMPTY_RECT: Rect = Rect { x: 0, y: 0, width: 0, height: 0 };
+		const EMPTY_RESP: CtrlHeader = CtrlHeader { ctrl_type: CtrlType::RespErrUnspec, flags: 0, fence_id: 0, ctx_id: 0, padding: 0 };
+		const EMPTY_XFER: TransferToHost2d = TransferToHost2d { hdr:         CtrlHeader { ctrl_type: CtrlType::CmdTransferToHost2d, flags: 0, fence_id: 0, ctx_id: 0, padding: 0 },
+		                                                        r:           EMPTY_RECT,
+		                                                        offset:      0,
+		                                                        resource_id: 0,
+		                                                        padding:     0, };
+		const EMPTY_FLUSH: ResourceFlush = ResourceFlush { hdr:         CtrlHeader { ctrl_type: CtrlType::CmdResourceFlush, flags: 0, fence_id: 0, ctx_id: 0, padding: 0 },
+		                                                   r:           EMPTY_RECT,
+		                                                   resource_id: 0,
+		                                                   padding:     0, };
+		Self { queue:               null_mut(),
+		       dev:                 null_mut(),
+		       idx:                 0,
+		       ack_used_idx:        0,
+		       cursor_queue:        null_mut(),
+		       cursor_idx:          0,
+		       cursor_ack_used_idx: 0,
+		       next_fence_id:       0,
+		       fenced_ack_idx:      0,
+		       pending:             [None; MAX_PENDING_FENCES],
+		       completed_fences:    [None; MAX_PENDING_FENCES],
+		       xfer_slots:          [EMPTY_XFER; MAX_PENDING_FENCES],
+		       flush_slots:         [EMPTY_FLUSH; MAX_PENDING_FENCES],
+		       fence_resp:          [EMPTY_RESP; MAX_PENDING_FENCES],
+		       resource_id:         0,
+		       width:               0,
+		       height:              0,
+		       fb:                  null_mut(), }
+	}
+
+	unsafe fn submit_chain(&mut self, cursor: bool, segments: &[(u64, u32, bool)]) -> u32 {
+		if cursor {
+			DescriptorChain::submit(self.dev, self.cursor_queue, &mut self.cursor_idx, &mut self.cursor_ack_used_idx, 1, segments).wait()
+		} else {
+			DescriptorChain::submit(self.dev, self.queue, &mut self.idx, &mut self.ack_used_idx, 0, segments).wait()
+		}
+	}
+
+	unsafe fn submit(&mut self, req_addr: u64, req_len: u32, resp_addr: u64, resp_len: u32) {
+		self.submit_chain(false, &[(req_addr, req_len, false), (resp_addr, resp_len, true)]);
+	}
+
+	unsafe fn submit_cursor(&mut self, req_addr: u64, req_len: u32, resp_addr: u64, resp_len: u32) {
+		self.submit_chain(true, &[(req_addr, req_len, false), (resp_addr, resp_len, true)]);
+	}
+
+	/// Move the hardware cursor to `pos`, optionally updating which
+	/// resource is used as the cursor image (`update_cursor`) or just
+	/// repositioning the existing one (`move_cursor`).
+	pub fn update_cursor(&mut self, resource_id: u32, hot_x: u32, hot_y: u32, pos: CursorPos) {
+		unsafe {
+			let req = UpdateCursor { hdr: CtrlHeader::new(CtrlType::CmdUpdateCursor), pos, resource_id, hot_x, hot_y, padding: 0 };
+			let mut resp = MaybeUninit::<CtrlHeader>::uninit();
+			self.submit_cursor(&req as *const _ as u64,
+			                   size_of::<UpdateCursor>() as u32,
+			                   resp.as_mut_ptr() as u64,
+			                   size_of::<CtrlHeader>() as u32);
+		}
+	}
+
+	pub fn move_cursor(&mut self, pos: CursorPos) {
+		unsafe {
+			let req = UpdateCursor { hdr: CtrlHeader::new(CtrlType::CmdMoveCursor), pos, resource_id: 0, hot_x: 0, hot_y: 0, padding: 0 };
+			let mut resp = MaybeUninit::<CtrlHeader>::uninit();
+			self.submit_cursor(&req as *const _ as u64,
+			                   size_of::<UpdateCursor>() as u32,
+			                   resp.as_mut_ptr() as u64,
+			                   size_of::<CtrlHeader>() as u32);
+		}
+	}
+
+	// Returns true iff the device's response was RespOkNoData -- the
+	// request explicitly wants callers to spin "until the device returns
+	// RespOkNoData", so a RespErrOutOfMemory/RespErrInvalidResourceId must
+	// be surfaced rather than silently treated as success.
+	unsafe fn create_resource_2d(&mut self, width: u32, height: u32) -> bool {
+		let req = ResourceCreate2d { hdr:         CtrlHeader::new(CtrlType::CmdResourceCreate2d),
+		                             resource_id: self.resource_id,
+		                             format:      Formats::B8G8R8A8Unorm,
+		                             width,
+		                             height, };
+		let mut resp = MaybeUninit::<CtrlHeader>::uninit();
+		self.submit(&req as *const _ as u64,
+		            size_of::<ResourceCreate2d>() as u32,
+		            resp.as_mut_ptr() as u64,
+		            size_of::<CtrlHeader>() as u32);
+		let raw_type = (resp.as_ptr() as *const u32).read_volatile();
+		resp_ctrl_type_is(raw_type, CtrlType::RespOkNoData)
+	}
+
+	unsafe fn attach_backing(&mut self, width: u32, height: u32) -> bool {
+		let fb_bytes = (width * height * 4) as usize;
+		let fb_pages = (fb_bytes + PAGE_SIZE - 1) / PAGE_SIZE;
+		self.fb = zalloc(fb_pages);
+
+		let req = AttachBacking { hdr: CtrlHeader::new(CtrlType::CmdResourceAttachBacking), resource_id: self.resource_id, nr_entries: 1 };
+		let entry = MemEntry { addr: self.fb as u64, length: fb_bytes as u32, padding: 0 };
+		let mut resp = MaybeUninit::<CtrlHeader>::uninit();
+		self.submit_chain(false,
+		                  &[(&req as *const _ as u64, size_of::<AttachBacking>() as u32, false),
+		                    (&entry as *const _ as u64, size_of::<MemEntry>() as u32, false),
+		                    (resp.as_mut_ptr() as u64, size_of::<CtrlHeader>() as u32, true)]);
+		let raw_type = (resp.as_ptr() as *const u32).read_volatile();
+		resp_ctrl_type_is(raw_type, CtrlType::RespOkNoData)
+	}
+
+	unsafe fn set_scanout(&mut self, scanout_id: u32, r: Rect) -> bool {
+		let req = SetScanout { hdr: CtrlHeader::new(CtrlType::CmdSetScanout), r, scanout_id, resource_id: self.resource_id };
+		let mut resp = MaybeUninit::<CtrlHeader>::uninit();
+		self.submit(&req as *const _ as u64,
+		            size_of::<SetScanout>() as u32,
+		            resp.as_mut_ptr() as u64,
+		            size_of::<CtrlHeader>() as u32);
+		let raw_type = (resp.as_ptr() as *const u32).read_volatile();
+		resp_ctrl_type_is(raw_type, CtrlType::RespOkNoData)
+	}
+
+	unsafe fn display_info(&mut self) -> RespDisplayInfo {
+		let req = CtrlHeader::new(CtrlType::CmdGetDisplayInfo);
+		let mut resp = MaybeUninit::<RespDisplayInfo>::uninit();
+		self.submit(&req as *const _ as u64,
+		            size_of::<CtrlHeader>() as u32,
+		            resp.as_mut_ptr() as u64,
+		            size_of::<RespDisplayInfo>() as u32);
+		// The device may reply short (e.g. an error with no pmodes filled
+		// in); only trust the buffer as a RespDisplayInfo once we've read
+		// back a ctrl_type the device could actually have written.
+		let raw_type = (resp.as_ptr() as *const u32).read_volatile();
+		if !resp_ctrl_type_is(raw_type, CtrlType::RespOkDisplayInfo) {
+			return RespDisplayInfo { hdr:    CtrlHeader::new(CtrlType::RespErrUnspec),
+			                          pmodes: [DisplayOne { r: Rect { x: 0, y: 0, width: 0, height: 0 }, enabled: 0, flags: 0 }; MAX_SCANOUTS], };
+		}
+		resp.assume_init()
+	}
+
+	unsafe fn get_edid(&mut self, scanout: u32) -> RespEdid {
+		let req = GetEdid { hdr: CtrlHeader::new(CtrlType::CmdGetEdid), scanout, padding: 0 };
+		let mut resp = MaybeUninit::<RespEdid>::uninit();
+		self.submit(&req as *const _ as u64,
+		            size_of::<GetEdid>() as u32,
+		            resp.as_mut_ptr() as u64,
+		            size_of::<RespEdid>() as u32);
+		// Same short-response concern as display_info: don't reinterpret
+		// whatever garbage is sitting in an uninitialized ctrl_type.
+		let raw_type = (resp.as_ptr() as *const u32).read_volatile();
+		if !resp_ctrl_type_is(raw_type, CtrlType::RespOkEdid) {
+			return RespEdid { hdr: CtrlHeader::new(CtrlType::RespErrUnspec), size: 0, padding: 0, edid: [0u8; 1024] };
+		}
+		resp.assume_init()
+	}
+
+	/// Ask the device what resolution scanout 0 actually wants, falling
+	/// back to `DEFAULT_WIDTH`/`DEFAULT_HEIGHT` if nothing is reported.
+	unsafe fn detect_mode(&mut self, edid_supported: bool) -> Rect {
+		let mut mode = Rect { x: 0, y: 0, width: DEFAULT_WIDTH, height: DEFAULT_HEIGHT };
+
+		let info = self.display_info();
+		let config = self.dev.add(MmioOffsets::Config.scale32()).cast::<Config>();
+		let num_scanouts = core::ptr::addr_of!((*config).num_scanouts).read_volatile() as usize;
+		let pmode = &info.pmodes[0];
+		if num_scanouts > 0 && pmode.enabled != 0 {
+			mode = Rect { x: 0, y: 0, width: pmode.r.width, height: pmode.r.height };
+		}
+
+		if edid_supported {
+			let edid = self.get_edid(0);
+			if let Some((width, height)) = parse_edid_preferred_mode(&edid.edid) {
+				mode.width = width;
+				mode.height = height;
+			}
+		}
+
+		mode
+	}
+
+	unsafe fn transfer_to_host_2d(&mut self, r: Rect) -> bool {
+		let offset = ((r.y * self.width + r.x) * 4) as u64;
+		let req = TransferToHost2d { hdr: CtrlHeader::new(CtrlType::CmdTransferToHost2d), r, offset, resource_id: self.resource_id, padding: 0 };
+		let mut resp = MaybeUninit::<CtrlHeader>::uninit();
+		self.submit(&req as *const _ as u64,
+		            size_of::<TransferToHost2d>() as u32,
+		            resp.as_mut_ptr() as u64,
+		            size_of::<CtrlHeader>() as u32);
+		let raw_type = (resp.as_ptr() as *const u32).read_volatile();
+		resp_ctrl_type_is(raw_type, CtrlType::RespOkNoData)
+	}
+
+	unsafe fn resource_flush(&mut self, r: Rect) -> bool {
+		let req = ResourceFlush { hdr: CtrlHeader::new(CtrlType::CmdResourceFlush), r, resource_id: self.resource_id, padding: 0 };
+		let mut resp = MaybeUninit::<CtrlHeader>::uninit();
+		self.submit(&req as *const _ as u64,
+		            size_of::<ResourceFlush>() as u32,
+		            resp.as_mut_ptr() as u64,
+		            size_of::<CtrlHeader>() as u32);
+		let raw_type = (resp.as_ptr() as *const u32).read_volatile();
+		resp_ctrl_type_is(raw_type, CtrlType::RespOkNoData)
+	}
+
+	fn alloc_fence_id(&mut self) -> u64 {
+		let fence_id = self.next_fence_id;
+		self.next_fence_id = self.next_fence_id.wrapping_add(1);
+		fence_id
+	}
+
+	// Reserve a free `pending` slot up front (before the device has even
+	// seen the command) so two back-to-back fenced submissions within the
+	// same call never race each other onto the same DMA buffer.
+	fn reserve_pending_slot(&mut self) -> Option<usize> {
+		for (i, slot) in self.pending.iter_mut().enumerate() {
+			if slot.is_none() {
+				*slot = Some((0, null(), false));
+				return Some(i);
+			}
+		}
+		None
+	}
+
+	// Submit a chain without waiting for it, remembering which descriptor
+	// head and response buffer the fence we just handed the device will
+	// show up on so `handle_interrupt` can recognize it later. `slot` must
+	// have already been reserved with `reserve_pending_slot`. `track_completion`
+	// says whether the caller will actually poll this command's fence id
+	// with `is_fence_complete` -- if not, `handle_interrupt` just frees the
+	// slot instead of publishing a fence id nobody will ever consume.
+	unsafe fn submit_fenced(&mut self, slot: usize, req_addr: u64, req_len: u32, resp_addr: *const CtrlHeader, resp_len: u32, track_completion: bool) {
+		// The used ring reports the head as a descriptor index, i.e. modulo
+		// the ring size, so record it the same way.
+		let head = self.idx % VIRTIO_RING_SIZE as u16;
+		// Record the real (head, resp_addr) *before* `submit` notifies the
+		// device, not after -- `submit` is what makes the completion
+		// observable to `handle_interrupt`, so recording it afterwards
+		// leaves a window where an interrupt can see the completed
+		// descriptor while this slot still holds the reserved placeholder
+		// `(0, null(), _)`, fail the `!resp.is_null()` guard, and drop the
+		// completion permanently even though `fenced_ack_idx` has already
+		// advanced past it.
+		self.pending[slot] = Some((head, resp_addr, track_completion));
+		DescriptorChain::submit(self.dev, self.queue, &mut self.idx, &mut self.fenced_ack_idx, 0, &[(req_addr, req_len, false), (resp_addr as u64, resp_len, true)]);
+	}
+
+	/// Kick off the transfer and flush for `r` without blocking, setting
+	/// `FLAG_FENCE` on the flush so its completion can be recognized from
+	/// `handle_interrupt`. Returns the flush's fence id, to poll with
+	/// `is_fence_complete`, or `None` if fewer than two of the
+	/// `MAX_PENDING_FENCES` pending slots are free (the transfer and the
+	/// flush each take one).
+	pub fn flush_async(&mut self, r: Rect) -> Option<u64> {
+		unsafe {
+			let xfer_slot = self.reserve_pending_slot()?;
+			let flush_slot = match self.reserve_pending_slot() {
+				Some(slot) => slot,
+				None => {
+					self.pending[xfer_slot] = None;
+					return None;
+				},
+			};
+
+			let xfer_fence = self.alloc_fence_id();
+			let offset = ((r.y * self.width + r.x) * 4) as u64;
+			self.xfer_slots[xfer_slot] = TransferToHost2d { hdr: CtrlHeader::new_fenced(CtrlType::CmdTransferToHost2d, xfer_fence),
+			                                                r,
+			                                                offset,
+			                                                resource_id: self.resource_id,
+			                                                padding: 0 };
+			let xfer_req_addr = &self.xfer_slots[xfer_slot] as *const _ as u64;
+			let xfer_resp_addr = &mut self.fence_resp[xfer_slot] as *mut _;
+			// Nobody polls the transfer's own fence id -- only the flush
+			// below is ever passed back to a caller -- so don't track its
+			// completion, or its slot in `completed_fences` would never be
+			// consumed and the pool fills up after a handful of flushes.
+			self.submit_fenced(xfer_slot, xfer_req_addr, size_of::<TransferToHost2d>() as u32, xfer_resp_addr, size_of::<CtrlHeader>() as u32, false);
+
+			let flush_fence = self.alloc_fence_id();
+			self.flush_slots[flush_slot] = ResourceFlush { hdr: CtrlHeader::new_fenced(CtrlType::CmdResourceFlush, flush_fence), r, resource_id: self.resource_id, padding: 0 };
+			let flush_req_addr = &self.flush_slots[flush_slot] as *const _ as u64;
+			let flush_resp_addr = &mut self.fence_resp[flush_slot] as *mut _;
+			self.submit_fenced(flush_slot, flush_req_addr, size_of::<ResourceFlush>() as u32, flush_resp_addr, size_of::<CtrlHeader>() as u32, true);
+
+			Some(flush_fence)
+		}
+	}
+
+	/// Returns true (and forgets about it) the first time `fence_id` is
+	/// seen in the completed set. Meant to be polled by whoever called
+	/// `flush_async` after `handle_interrupt` has had a chance to run.
+	pub fn is_fence_complete(&mut self, fence_id: u64) -> bool {
+		for slot in self.completed_fences.iter_mut() {
+			if *slot == Some(fence_id) {
+				*slot = None;
+				return true;
+			}
+		}
+		false
+	}
+
+	/// Service the controlq's used-ring interrupt: ack it, then walk every
+	/// newly completed descriptor looking for ones `flush_async` is
+	/// tracking, recording their fence id as complete. Trails the used
+	/// ring with its own `fenced_ack_idx` rather than `ack_used_idx` so it
+	/// never steals the completion a concurrent `DescriptorChain::wait`
+	/// (from one of the synchronous calls) is spinning on.
+	pub fn handle_interrupt(&mut self) {
+		unsafe {
+			let status = self.dev.add(MmioOffsets::InterruptStatus.scale32()).read_volatile();
+			if status & 0x1 != 0 {
+				let queue = &mut *self.queue;
+				while self.fenced_ack_idx != queue.used.idx {
+					let desc_id = queue.used.ring[self.fenced_ack_idx as usize % VIRTIO_RING_SIZE].id as u16;
+					for slot in self.pending.iter_mut() {
+						if let Some((head, resp, track_completion)) = *slot {
+							// A non-null resp means the slot has actually
+							// been submitted; a reserved-but-not-yet-
+							// submitted placeholder (resp still null)
+							// must never be matched against a completion.
+							if head == desc_id && !resp.is_null() {
+								*slot = None;
+								// Only commands a caller will actually poll
+								// via `is_fence_complete` get a slot in
+								// `completed_fences` -- otherwise an
+								// untracked completion (flush_async's
+								// transfer) would sit there forever and
+								// eventually starve the pool.
+								if track_completion {
+									let fence_id = (*resp).fence_id;
+									for c in self.completed_fences.iter_mut() {
+										if c.is_none() {
+											*c = Some(fence_id);
+											break;
+										}
+									}
+								}
+								break;
+							}
+						}
+					}
+					self.fenced_ack_idx = self.fenced_ack_idx.wrapping_add(1);
+				}
+			}
+			self.dev.add(MmioOffsets::InterruptAck.scale32()).write_volatile(status);
+		}
+	}
+
+	/// Push the given rectangle of the guest-side backing to the host
+	/// resource and ask the device to composite it onto the scanout.
+	pub fn flush(&mut self, r: Rect) -> bool {
+		unsafe { self.transfer_to_host_2d(Rect { x: r.x, y: r.y, width: r.width, height: r.height }) && self.resource_flush(r) }
+	}
+
+	/// Fill a rectangle of the framebuffer backing with a solid BGRA color.
+	/// Silently does nothing if `r` doesn't fit within the scanout or the
+	/// backing hasn't been attached yet, rather than writing out of bounds.
+	pub fn fill_rect(&mut self, r: &Rect, color: u32) {
+		// checked_add (rather than plain `+`) so a Rect crafted to overflow
+		// u32 (e.g. x near u32::MAX) can't wrap into a sum that passes this
+		// check and then index the framebuffer out of bounds.
+		let right = r.x.checked_add(r.width);
+		let bottom = r.y.checked_add(r.height);
+		let in_bounds = matches!(right, Some(right) if right <= self.width) && matches!(bottom, Some(bottom) if bottom <= self.height);
+		if self.fb.is_null() || !in_bounds {
+			return;
+		}
+		unsafe {
+			for y in 0..r.height {
+				for x in 0..r.width {
+					let px = ((r.y + y) * self.width + (r.x + x)) as isize;
+					self.fb.cast::<u32>().offset(px).write_volatile(color);
+				}
+			}
+		}
+	}
+
+	/// Copy a rectangle of BGRA pixels into the framebuffer backing.
+	/// Silently does nothing if `r` doesn't fit within the scanout, `pixels`
+	/// is too short to cover it, or the backing hasn't been attached yet,
+	/// rather than reading or writing out of bounds.
+	pub fn blit(&mut self, r: &Rect, pixels: &[u32]) {
+		// checked_add/checked_mul (rather than plain `+`/`*`) so a Rect
+		// crafted to overflow u32 can't wrap past these checks and then
+		// index the framebuffer or `pixels` out of bounds.
+		let right = r.x.checked_add(r.width);
+		let bottom = r.y.checked_add(r.height);
+		let area = r.width.checked_mul(r.height);
+		let in_bounds = matches!(right, Some(right) if right <= self.width)
+			&& matches!(bottom, Some(bottom) if bottom <= self.height)
+			&& matches!(area, Some(area) if pixels.len() >= area as usize);
+		if self.fb.is_null() || !in_bounds {
+			return;
+		}
+		unsafe {
+			for y in 0..r.height {
+				let dst_off = ((r.y + y) * self.width + r.x) as isize;
+				let src_off = (y * r.width) as usize;
+				let src_row = &pixels[src_off..src_off + r.width as usize];
+				core::ptr::copy_nonoverlapping(src_row.as_ptr(), self.fb.cast::<u32>().offset(dst_off), r.width as usize);
+			}
+		}
 	}
 }
 
@@ -213,6 +800,12 @@ static mut GPU_DEVICES: [Option<Device>; 8] = [
 	None,
 ];
 
+/// The first initialized GPU device, if any. The input subsystem uses
+/// this to feed mouse motion into the hardware cursor.
+pub fn primary_device() -> Option<&'static mut Device> {
+	unsafe { GPU_DEVICES.iter_mut().find_map(Option::as_mut) }
+}
+
 pub fn setup_gpu_device(ptr: *mut u32) -> bool {
 	unsafe {
 		// We can get the index of the device based on its address.
@@ -231,10 +824,30 @@ pub fn setup_gpu_device(ptr: *mut u32) -> bool {
 		// 3. Set the DRIVER status bit
 		status_bits |= StatusField::DriverOk.val32();
 		ptr.add(MmioOffsets::Status.scale32()).write_volatile(status_bits);
+		// The modern (non-legacy) MMIO transport reports Version == 2;
+		// legacy devices report Version == 1. We use this to decide
+		// whether to program queues through QueuePfn or through the
+		// split QueueDesc/QueueDriver/QueueDevice registers.
+		let device_version = ptr.add(MmioOffsets::Version.scale32()).read_volatile();
 		// 4. Read device feature bits, write subset of feature
 		// bits understood by OS and driver    to the device.
+		//
+		// Feature word 1 (selector 1) is where VIRTIO_F_VERSION_1 lives.
+		// Only take the modern split-queue path if the device actually
+		// offers it -- otherwise fall back to the legacy PFN layout.
+		ptr.add(MmioOffsets::HostFeaturesSel.scale32()).write_volatile(1);
+		let host_features_hi = ptr.add(MmioOffsets::HostFeatures.scale32()).read_volatile();
+		let version_1_offered = device_version >= 2 && host_features_hi & VIRTIO_F_VERSION_1 != 0;
+		ptr.add(MmioOffsets::HostFeaturesSel.scale32()).write_volatile(0);
+
 		let host_features = ptr.add(MmioOffsets::HostFeatures.scale32()).read_volatile();
-		ptr.add(MmioOffsets::GuestFeatures.scale32()).write_volatile(host_features);
+		let edid_supported = host_features & (1 << F_EDID) != 0;
+		ptr.add(MmioOffsets::GuestFeatures.scale32()).write_volatile(host_features & (1 << F_EDID));
+		if version_1_offered {
+			ptr.add(MmioOffsets::GuestFeaturesSel.scale32()).write_volatile(1);
+			ptr.add(MmioOffsets::GuestFeatures.scale32()).write_volatile(VIRTIO_F_VERSION_1);
+			ptr.add(MmioOffsets::GuestFeaturesSel.scale32()).write_volatile(0);
+		}
 		// 5. Set the FEATURES_OK status bit
 		status_bits |= StatusField::FeaturesOk.val32();
 		ptr.add(MmioOffsets::Status.scale32()).write_volatile(status_bits);
@@ -251,15 +864,6 @@ pub fn setup_gpu_device(ptr: *mut u32) -> bool {
 			return false;
 		}
 		// 7. Perform device-specific setup.
-		// Set the queue num. We have to make sure that the
-		// queue size is valid because the device can only take
-		// a certain size.
-		let qnmax = ptr.add(MmioOffsets::QueueNumMax.scale32()).read_volatile();
-		ptr.add(MmioOffsets::QueueNum.scale32()).write_volatile(VIRTIO_RING_SIZE as u32);
-		if VIRTIO_RING_SIZE as u32 > qnmax {
-			print!("queue size fail...");
-			return false;
-		}
 		// First, if the block device array is empty, create it!
 		// We add 4095 to round this up and then do an integer
 		// divide to truncate the decimal. We don't add 4096,
@@ -267,40 +871,54 @@ pub fn setup_gpu_device(ptr: *mut u32) -> bool {
 		// pages, not one.
 		let num_pages = (size_of::<Queue>() + PAGE_SIZE - 1) / PAGE_SIZE;
 		// println!("np = {}", num_pages);
-		// We allocate a page for each device. This will the the
-		// descriptor where we can communicate with the block
-		// device. We will still use an MMIO register (in
-		// particular, QueueNotify) to actually tell the device
-		// we put something in memory. We also have to be
-		// careful with memory ordering. We don't want to
-		// issue a notify before all memory writes have
-		// finished. We will look at that later, but we need
-		// what is called a memory "fence" or barrier.
-		ptr.add(MmioOffsets::QueueSel.scale32()).write_volatile(0);
-		// Alignment is very important here. This is the memory address
-		// alignment between the available and used rings. If this is wrong,
-		// then we and the device will refer to different memory addresses
-		// and hence get the wrong data in the used ring.
-		// ptr.add(MmioOffsets::QueueAlign.scale32()).write_volatile(2);
-		let queue_ptr = zalloc(num_pages) as *mut Queue;
-		let queue_pfn = queue_ptr as u32;
-		ptr.add(MmioOffsets::GuestPageSize.scale32()).write_volatile(PAGE_SIZE as u32);
-		// QueuePFN is a physical page number, however it
-		// appears for QEMU we have to write the entire memory
-		// address. This is a physical memory address where we
-		// (the OS) and the block device have in common for
-		// making and receiving requests.
-		ptr.add(MmioOffsets::QueuePfn.scale32()).write_volatile(queue_pfn / PAGE_SIZE as u32);
+		// controlq (index 0) carries the 2D/display commands; cursorq
+		// (index 1) carries cursor updates. Set the queue num for each,
+		// making sure the size is valid because the device can only
+		// take a certain size, then program its ring addresses through
+		// either the legacy or modern transport.
+		let queue_ptr = match setup_queue(ptr, 0, version_1_offered, num_pages) {
+			Some(q) => q,
+			None => {
+				print!("queue size fail...");
+				return false;
+			},
+		};
+		let cursor_queue_ptr = match setup_queue(ptr, 1, version_1_offered, num_pages) {
+			Some(q) => q,
+			None => {
+				print!("cursor queue size fail...");
+				return false;
+			},
+		};
+
 		// 8. Set the DRIVER_OK status bit. Device is now "live"
 		status_bits |= StatusField::DriverOk.val32();
 		ptr.add(MmioOffsets::Status.scale32()).write_volatile(status_bits);
 
-		let dev = Device {
-			queue: queue_ptr,
-			dev: ptr,
-			idx: 0,
-			ack_used_idx: 0,
-		};
+		let mut dev = Device::new();
+		dev.queue = queue_ptr;
+		dev.dev = ptr;
+		dev.cursor_queue = cursor_queue_ptr;
+		dev.resource_id = 1;
+		dev.width = DEFAULT_WIDTH;
+		dev.height = DEFAULT_HEIGHT;
+
+		let mode = dev.detect_mode(edid_supported);
+		dev.width = mode.width;
+		dev.height = mode.height;
+
+		if !dev.create_resource_2d(dev.width, dev.height) {
+			print!("resource create fail...");
+			return false;
+		}
+		if !dev.attach_backing(dev.width, dev.height) {
+			print!("attach backing fail...");
+			return false;
+		}
+		if !dev.set_scanout(0, Rect { x: 0, y: 0, width: dev.width, height: dev.height }) {
+			print!("set scanout fail...");
+			return false;
+		}
 
 		GPU_DEVICES[idx] = Some(dev);
 
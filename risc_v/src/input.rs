@@ -0,0 +1,308 @@
+// input.rs
+// Virtio keyboard/mouse driver
+// Stephen Marz
+// 20 May 2020
+
+#![allow(dead_code)]
+use crate::{gpu,
+            page::{zalloc, PAGE_SIZE},
+            virtio,
+            virtio::{Descriptor, MmioOffsets, Queue, StatusField, VIRTIO_RING_SIZE, VIRTQ_DESC_F_WRITE}};
+use core::{mem::size_of,
+           ptr::{addr_of, addr_of_mut, null_mut},
+           sync::atomic::{fence, Ordering}};
+
+pub const VIRTIO_INPUT_DEV_ID: u32 = 18;
+
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+
+pub const REL_X: u16 = 0x00;
+pub const REL_Y: u16 = 0x01;
+
+pub const ABS_X: u16 = 0x00;
+pub const ABS_Y: u16 = 0x01;
+
+#[repr(u8)]
+pub enum ConfigSelect {
+	Unset    = 0x00,
+	IdName   = 0x01,
+	IdSerial = 0x02,
+	IdDevids = 0x03,
+	PropBits = 0x10,
+	EvBits   = 0x11,
+	AbsInfo  = 0x12,
+}
+
+#[repr(C)]
+pub struct AbsInfo {
+	min:  u32,
+	max:  u32,
+	fuzz: u32,
+	flat: u32,
+	res:  u32,
+}
+
+#[repr(C)]
+pub struct DevIds {
+	bustype: u16,
+	vendor:  u16,
+	product: u16,
+	version: u16,
+}
+
+#[repr(C)]
+pub union Payload {
+	string: [u8; 128],
+	bitmap: [u8; 128],
+	abs:    AbsInfo,
+	ids:    DevIds,
+}
+
+#[repr(C)]
+pub struct Config {
+	select:   u8,
+	subsel:   u8,
+	size:     u8,
+	reserved: [u8; 5],
+	payload:  Payload,
+}
+
+// The 8-byte event struct virtio-input writes into each eventq buffer.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Event {
+	kind:  u16,
+	code:  u16,
+	value: u32,
+}
+
+const KEY_BUFFER_SIZE: usize = 64;
+static mut KEY_BUFFER: [u16; KEY_BUFFER_SIZE] = [0; KEY_BUFFER_SIZE];
+static mut KEY_HEAD: usize = 0;
+static mut KEY_TAIL: usize = 0;
+
+fn push_key(code: u16, pressed: bool) {
+	unsafe {
+		let next = (KEY_HEAD + 1) % KEY_BUFFER_SIZE;
+		// Drop the event rather than overwrite the tail if the console
+		// isn't keeping up.
+		if next != KEY_TAIL {
+			KEY_BUFFER[KEY_HEAD] = if pressed { code | 0x8000 } else { code };
+			KEY_HEAD = next;
+		}
+	}
+}
+
+/// Pop the oldest keycode event for the console/shell to consume. Bit 15
+/// set means key-down, clear means key-up.
+pub fn pop_key() -> Option<u16> {
+	unsafe {
+		if KEY_TAIL == KEY_HEAD {
+			None
+		}
+		else {
+			let code = KEY_BUFFER[KEY_TAIL];
+			KEY_TAIL = (KEY_TAIL + 1) % KEY_BUFFER_SIZE;
+			Some(code)
+		}
+	}
+}
+
+pub struct Device {
+	queue:        *mut Queue,
+	status_queue: *mut Queue,
+	events:       *mut Event,
+	dev:          *mut u32,
+	ack_used_idx: u16,
+	has_key:      bool,
+	has_rel:      bool,
+	has_abs:      bool,
+	x:            u32,
+	y:            u32,
+}
+impl Device {
+	pub const fn new() -> Self {
+		Self { queue:        null_mut(),
+		       status_queue: null_mut(),
+		       events:       null_mut(),
+		       dev:          null_mut(),
+		       ack_used_idx: 0,
+		       has_key:      false,
+		       has_rel:      false,
+		       has_abs:      false,
+		       x:            0,
+		       y:            0, }
+	}
+
+	// Select EV_BITS for `ev_type` in the config selection window and
+	// see whether the device reports any bits at all for it.
+	unsafe fn supports_ev(&self, ev_type: u16) -> bool {
+		let config = self.dev.add(MmioOffsets::Config.scale32()).cast::<Config>();
+		addr_of_mut!((*config).select).write_volatile(ConfigSelect::EvBits as u8);
+		addr_of_mut!((*config).subsel).write_volatile(ev_type as u8);
+		addr_of!((*config).size).read_volatile() > 0
+	}
+
+	/// Drain every event the device has completed since we last looked,
+	/// decode it, and re-post its buffer so the device can reuse it.
+	pub fn drain_events(&mut self) {
+		unsafe {
+			let status = self.dev.add(MmioOffsets::InterruptStatus.scale32()).read_volatile();
+			if status & 0x1 != 0 {
+				let queue = &mut *self.queue;
+				let mut drained = false;
+				while self.ack_used_idx != queue.used.idx {
+					let used = &queue.used.ring[self.ack_used_idx as usize % VIRTIO_RING_SIZE];
+					let desc_id = used.id as usize;
+					let ev = self.events.add(desc_id).read_volatile();
+					self.handle_event(ev);
+
+					let avail_idx = queue.avail.idx as usize % VIRTIO_RING_SIZE;
+					queue.avail.ring[avail_idx] = desc_id as u16;
+					queue.avail.idx = queue.avail.idx.wrapping_add(1);
+
+					self.ack_used_idx = self.ack_used_idx.wrapping_add(1);
+					drained = true;
+				}
+				if drained {
+					fence(Ordering::SeqCst);
+					self.dev.add(MmioOffsets::QueueNotify.scale32()).write_volatile(0);
+				}
+			}
+			// virtio-mmio interrupts are level-triggered: until we write
+			// InterruptAck the device (or the PLIC) keeps asserting the
+			// IRQ, so without this the first event would re-enter the
+			// handler forever instead of draining and returning.
+			self.dev.add(MmioOffsets::InterruptAck.scale32()).write_volatile(status);
+		}
+	}
+
+	fn handle_event(&mut self, ev: Event) {
+		match ev.kind {
+			EV_REL if self.has_rel => self.handle_motion(ev.code, ev.value as i32, true),
+			EV_ABS if self.has_abs => self.handle_motion(ev.code, ev.value as i32, false),
+			EV_KEY if self.has_key => push_key(ev.code, ev.value != 0),
+			_ => {},
+		}
+	}
+
+	fn handle_motion(&mut self, code: u16, value: i32, relative: bool) {
+		match (code, relative) {
+			(REL_X, true) => self.x = (self.x as i32 + value).max(0) as u32,
+			(REL_Y, true) => self.y = (self.y as i32 + value).max(0) as u32,
+			(ABS_X, false) => self.x = value as u32,
+			(ABS_Y, false) => self.y = value as u32,
+			_ => return,
+		}
+		if let Some(gpu_dev) = gpu::primary_device() {
+			gpu_dev.move_cursor(gpu::CursorPos::new(0, self.x, self.y));
+		}
+	}
+}
+
+static mut INPUT_DEVICES: [Option<Device>; 8] = [None, None, None, None, None, None, None, None];
+
+/// Route completed events on every probed input device to the console
+/// and the GPU cursor. Call this from the virtio interrupt handler.
+pub fn poll_all() {
+	unsafe {
+		for dev in INPUT_DEVICES.iter_mut().flatten() {
+			dev.drain_events();
+		}
+	}
+}
+
+pub fn setup_input_device(ptr: *mut u32) -> bool {
+	unsafe {
+		let idx = (ptr as usize - virtio::MMIO_VIRTIO_START) >> 12;
+		// [Driver] Device Initialization
+		ptr.add(MmioOffsets::Status.scale32()).write_volatile(0);
+		let mut status_bits = StatusField::Acknowledge.val32();
+		ptr.add(MmioOffsets::Status.scale32()).write_volatile(status_bits);
+		status_bits |= StatusField::DriverOk.val32();
+		ptr.add(MmioOffsets::Status.scale32()).write_volatile(status_bits);
+
+		// Same version detection gpu.rs does for the controlq/cursorq, so
+		// the eventq/statusq take the matching transport instead of always
+		// falling back to the legacy PFN layout.
+		let device_version = ptr.add(MmioOffsets::Version.scale32()).read_volatile();
+		ptr.add(MmioOffsets::HostFeaturesSel.scale32()).write_volatile(1);
+		let host_features_hi = ptr.add(MmioOffsets::HostFeatures.scale32()).read_volatile();
+		let version_1_offered = device_version >= 2 && host_features_hi & gpu::VIRTIO_F_VERSION_1 != 0;
+		ptr.add(MmioOffsets::HostFeaturesSel.scale32()).write_volatile(0);
+
+		let host_features = ptr.add(MmioOffsets::HostFeatures.scale32()).read_volatile();
+		ptr.add(MmioOffsets::GuestFeatures.scale32()).write_volatile(host_features);
+		if version_1_offered {
+			ptr.add(MmioOffsets::GuestFeaturesSel.scale32()).write_volatile(1);
+			ptr.add(MmioOffsets::GuestFeatures.scale32()).write_volatile(gpu::VIRTIO_F_VERSION_1);
+			ptr.add(MmioOffsets::GuestFeaturesSel.scale32()).write_volatile(0);
+		}
+		status_bits |= StatusField::FeaturesOk.val32();
+		ptr.add(MmioOffsets::Status.scale32()).write_volatile(status_bits);
+		let status_ok = ptr.add(MmioOffsets::Status.scale32()).read_volatile();
+		if false == StatusField::features_ok(status_ok) {
+			print!("input features fail...");
+			ptr.add(MmioOffsets::Status.scale32()).write_volatile(StatusField::Failed.val32());
+			return false;
+		}
+
+		let num_pages = (size_of::<Queue>() + PAGE_SIZE - 1) / PAGE_SIZE;
+		// eventq (index 0) delivers key/motion events to us; statusq
+		// (index 1) carries LED/repeat-rate updates from us. Without
+		// setting up statusq the device can stall waiting on buffers
+		// we never gave it.
+		let queue_ptr = match gpu::setup_queue(ptr, 0, version_1_offered, num_pages) {
+			Some(q) => q,
+			None => {
+				print!("input eventq size fail...");
+				return false;
+			},
+		};
+		let status_queue_ptr = match gpu::setup_queue(ptr, 1, version_1_offered, num_pages) {
+			Some(q) => q,
+			None => {
+				print!("input statusq size fail...");
+				return false;
+			},
+		};
+
+		status_bits |= StatusField::DriverOk.val32();
+		ptr.add(MmioOffsets::Status.scale32()).write_volatile(status_bits);
+
+		let mut dev = Device { queue: queue_ptr,
+		                       status_queue: status_queue_ptr,
+		                       events: null_mut(),
+		                       dev: ptr,
+		                       ack_used_idx: 0,
+		                       has_key: false,
+		                       has_rel: false,
+		                       has_abs: false,
+		                       x: 0,
+		                       y: 0 };
+		dev.has_key = dev.supports_ev(EV_KEY);
+		dev.has_rel = dev.supports_ev(EV_REL);
+		dev.has_abs = dev.supports_ev(EV_ABS);
+
+		// Pre-fill the eventq with a device-writable buffer per ring
+		// slot so the device has somewhere to write events as they
+		// happen, rather than us having to post one per event.
+		let events = zalloc(1) as *mut Event;
+		dev.events = events;
+		let queue = &mut *queue_ptr;
+		for i in 0..VIRTIO_RING_SIZE {
+			queue.desc[i] = Descriptor { addr: events.add(i) as u64, len: size_of::<Event>() as u32, flags: VIRTQ_DESC_F_WRITE, next: 0 };
+			queue.avail.ring[i] = i as u16;
+		}
+		fence(Ordering::SeqCst);
+		queue.avail.idx = VIRTIO_RING_SIZE as u16;
+		ptr.add(MmioOffsets::QueueNotify.scale32()).write_volatile(0);
+
+		INPUT_DEVICES[idx] = Some(dev);
+
+		true
+	}
+}
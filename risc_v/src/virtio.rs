@@ -0,0 +1,121 @@
+// virtio.rs
+// Shared virtio MMIO transport: register layout, descriptor/queue memory
+// layout, and status-field helpers used by every virtio device driver
+// (gpu.rs, input.rs, ...).
+// Stephen Marz
+// 12 May 2020
+
+#![allow(dead_code)]
+
+// QEMU's virt machine maps 8 virtio-mmio slots back to back starting here,
+// one page apart.
+pub const MMIO_VIRTIO_START: usize = 0x1000_1000;
+pub const MMIO_VIRTIO_END: usize = 0x1000_8000;
+
+// A single descriptor ring slot: one readable or device-writable buffer,
+// optionally chained to the next descriptor in the request.
+#[repr(C)]
+pub struct Descriptor {
+	pub addr:  u64,
+	pub len:   u32,
+	pub flags: u16,
+	pub next:  u16,
+}
+
+pub const VIRTIO_RING_SIZE: usize = 1 << 7;
+
+pub const VIRTQ_DESC_F_NEXT: u16 = 1;
+pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+pub struct Avail {
+	pub flags: u16,
+	pub idx:   u16,
+	pub ring:  [u16; VIRTIO_RING_SIZE],
+}
+
+#[repr(C)]
+pub struct UsedElem {
+	pub id:  u32,
+	pub len: u32,
+}
+
+#[repr(C)]
+pub struct Used {
+	pub flags: u16,
+	pub idx:   u16,
+	pub ring:  [UsedElem; VIRTIO_RING_SIZE],
+}
+
+// The three rings a virtqueue is made of, in one page-sized allocation
+// shared between the legacy (QueuePfn) and modern (QueueDesc/QueueDriver/
+// QueueDevice) transports.
+#[repr(C)]
+pub struct Queue {
+	pub desc:  [Descriptor; VIRTIO_RING_SIZE],
+	pub avail: Avail,
+	pub used:  Used,
+}
+
+// Byte offsets into a virtio-mmio device's register window. `scale32`
+// converts a byte offset into a `*mut u32` index since every caller talks
+// to the device through a `*mut u32`.
+#[repr(usize)]
+pub enum MmioOffsets {
+	MagicValue       = 0x000,
+	Version          = 0x004,
+	DeviceId         = 0x008,
+	VendorId         = 0x00c,
+	HostFeatures     = 0x010,
+	HostFeaturesSel  = 0x014,
+	GuestFeatures    = 0x020,
+	GuestFeaturesSel = 0x024,
+	// GuestPageSize, QueueAlign and QueuePfn are legacy-only (pre
+	// VIRTIO_F_VERSION_1); QueueSel/QueueNumMax/QueueNum serve both.
+	GuestPageSize    = 0x028,
+	QueueSel         = 0x030,
+	QueueNumMax      = 0x034,
+	QueueNum         = 0x038,
+	QueueAlign       = 0x03c,
+	QueuePfn         = 0x040,
+	// QueueReady and the QueueDesc/QueueDriver/QueueDevice pairs are the
+	// modern (VIRTIO_F_VERSION_1) split-queue registers; QueueNotify, the
+	// interrupt registers and Status serve both transports.
+	QueueReady       = 0x044,
+	QueueNotify      = 0x050,
+	InterruptStatus  = 0x060,
+	InterruptAck     = 0x064,
+	Status           = 0x070,
+	QueueDescLow     = 0x080,
+	QueueDescHigh    = 0x084,
+	QueueDriverLow   = 0x090,
+	QueueDriverHigh  = 0x094,
+	QueueDeviceLow   = 0x0a0,
+	QueueDeviceHigh  = 0x0a4,
+	// Device-specific configuration space.
+	Config           = 0x100,
+}
+impl MmioOffsets {
+	pub fn scale32(self) -> usize {
+		self as usize / 4
+	}
+}
+
+#[repr(u32)]
+pub enum StatusField {
+	Acknowledge      = 1,
+	Driver           = 2,
+	Failed           = 128,
+	FeaturesOk       = 8,
+	DriverOk         = 4,
+	DeviceNeedsReset = 64,
+}
+impl StatusField {
+	pub fn val32(self) -> u32 {
+		self as u32
+	}
+
+	pub fn features_ok(status: u32) -> bool {
+		status & StatusField::FeaturesOk.val32() != 0
+	}
+}